@@ -0,0 +1,40 @@
+//! Transparent decompression for ZDoom's compressed ("ZNOD"/"ZGLN") node
+//! lumps, so `nodes::read_extended_*` sees the same payload either way.
+
+extern crate flate;
+
+use std::vec::Vec;
+
+#[deriving(Copy, PartialEq, Show)]
+pub enum Compression {
+    None,
+    Zlib,
+}
+
+impl Compression {
+    fn sniff(magic: &[u8]) -> Compression {
+        match magic {
+            b"ZNOD" | b"ZGLN" => Compression::Zlib,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Strips `raw`'s 4-byte signature and, if it names a compressed
+/// format, inflates the remainder. Returns the same kind of payload
+/// `nodes::read_extended_*` expects to find right after the "XNOD"
+/// signature in an uncompressed lump.
+pub fn decode_lump(raw: &[u8]) -> Vec<u8> {
+    if raw.len() < 4 {
+        return Vec::new();
+    }
+    match Compression::sniff(raw.slice(0, 4)) {
+        Compression::None => raw.slice_from(4).to_vec(),
+        Compression::Zlib => {
+            flate::inflate_bytes_zlib(raw.slice_from(4))
+                .expect("corrupt compressed node lump")
+                .as_slice()
+                .to_vec()
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! A small little-endian cursor over a raw lump buffer, shared by the
+//! lump parsers (`nodes`, `hexen`) that can't use `Archive::read_lump`'s
+//! fixed-width struct path.
+
+pub struct Cursor<'a> {
+    data: &'a [u8],
+    pub pos: uint,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(data: &'a [u8]) -> Cursor<'a> { Cursor { data: data, pos: 0 } }
+
+    pub fn len(&self) -> uint { self.data.len() }
+
+    pub fn u8(&mut self) -> u8 {
+        let v = self.data[self.pos];
+        self.pos += 1;
+        v
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        let v = self.data[self.pos] as u16 |
+                (self.data[self.pos + 1] as u16 << 8);
+        self.pos += 2;
+        v
+    }
+
+    pub fn i16(&mut self) -> i16 { self.u16() as i16 }
+
+    pub fn u32(&mut self) -> u32 {
+        let v = self.data[self.pos] as u32 |
+                (self.data[self.pos + 1] as u32 << 8) |
+                (self.data[self.pos + 2] as u32 << 16) |
+                (self.data[self.pos + 3] as u32 << 24);
+        self.pos += 4;
+        v
+    }
+
+    pub fn i32(&mut self) -> i32 { self.u32() as i32 }
+
+    /// Reads a fixed-size 5-byte array, the width of a Hexen linedef or
+    /// thing special's argument list.
+    pub fn bytes5(&mut self) -> [u8, ..5] {
+        [self.u8(), self.u8(), self.u8(), self.u8(), self.u8()]
+    }
+}
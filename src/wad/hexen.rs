@@ -0,0 +1,116 @@
+//! Hexen-format `THINGS`/`LINEDEFS` support: detects the `BEHAVIOR` lump
+//! Hexen maps carry and parses the wider Hexen records into data
+//! parallel to `Level::things`/`linedefs`, alongside the vanilla fields.
+
+use std::vec::Vec;
+use super::archive::Archive;
+use super::cursor::Cursor;
+use super::types::{WadName, WadThing, WadLinedef};
+
+// BEHAVIOR follows SECTORS, REJECT and BLOCKMAP in the Hexen lump
+// sequence, i.e. SECTORS_OFFSET (8) + 3.
+static BEHAVIOR_OFFSET: uint = 11;
+
+/// The map format detected for a level, exposed on `Level` so renderers
+/// and future scripting support can branch on it.
+#[deriving(Copy, PartialEq, Show)]
+pub enum MapFormat {
+    Doom,
+    Hexen,
+}
+
+impl MapFormat {
+    /// A Hexen-format map is identified by a `BEHAVIOR` lump following
+    /// `SECTORS`/`REJECT`/`BLOCKMAP`.
+    pub fn detect(wad: &Archive, start_index: uint) -> MapFormat {
+        match wad.lump_name(start_index + BEHAVIOR_OFFSET) {
+            Some(name) if name == WadName::from_str("BEHAVIOR") => MapFormat::Hexen,
+            _ => MapFormat::Doom,
+        }
+    }
+}
+
+
+/// The fields a Hexen `THING` record carries beyond `WadThing`.
+pub struct HexenThingExtra {
+    pub tid: u16,
+    pub z_height: i16,
+    pub special: u8,
+    pub args: [u8, ..5],
+}
+
+/// The fields a Hexen `LINEDEF` record carries beyond `WadLinedef`:
+/// a byte special and five generic args in place of Doom's
+/// `special_type`/`sector_tag`, plus the activation flags packed into
+/// the upper bits of `flags`.
+pub struct HexenLinedefExtra {
+    pub special: u8,
+    pub args: [u8, ..5],
+    pub activation: u16,
+}
+
+static ACTIVATION_MASK: i16 = 0x1c00;
+
+/// Parses the Hexen `THINGS` lump. Its 20-byte records share Doom's
+/// `x`/`y`/`angle`/`thing_type`/`flags` fields but reorder and widen
+/// them around the new `tid` and `z_height` fields, so they can't be
+/// read with the vanilla `WadThing` lump parser; this builds both the
+/// plain `WadThing` and its Hexen extras from the same record.
+pub fn read_hexen_things(wad: &mut Archive, index: uint)
+        -> Vec<(WadThing, HexenThingExtra)> {
+    let raw: Vec<u8> = wad.read_lump(index);
+    let mut cursor = Cursor::new(raw.as_slice());
+    let mut things = Vec::new();
+    while cursor.pos < cursor.len() {
+        let tid = cursor.u16();
+        let x = cursor.i16();
+        let y = cursor.i16();
+        let z_height = cursor.i16();
+        let angle = cursor.i16();
+        let thing_type = cursor.i16();
+        let flags = cursor.i16();
+        let special = cursor.u8();
+        let args = cursor.bytes5();
+        things.push((
+            WadThing { x: x, y: y, angle: angle, thing_type: thing_type,
+                      flags: flags },
+            HexenThingExtra {
+                tid: tid, z_height: z_height, special: special, args: args,
+            },
+        ));
+    }
+    things
+}
+
+/// Parses the Hexen `LINEDEFS` lump. Its 16-byte records replace Doom's
+/// `special_type`/`sector_tag` pair with a byte special and five
+/// generic args, so like `read_hexen_things` this builds both the plain
+/// `WadLinedef` and its Hexen extras from the same record.
+pub fn read_hexen_linedefs(wad: &mut Archive, index: uint)
+        -> Vec<(WadLinedef, HexenLinedefExtra)> {
+    let raw: Vec<u8> = wad.read_lump(index);
+    let mut cursor = Cursor::new(raw.as_slice());
+    let mut linedefs = Vec::new();
+    while cursor.pos < cursor.len() {
+        let start_vertex = cursor.u16();
+        let end_vertex = cursor.u16();
+        let flags = cursor.i16();
+        let special = cursor.u8();
+        let args = cursor.bytes5();
+        let right_side = cursor.i16();
+        let left_side = cursor.i16();
+        linedefs.push((
+            WadLinedef {
+                start_vertex: start_vertex, end_vertex: end_vertex,
+                flags: flags, special_type: 0, sector_tag: 0,
+                right_side: right_side, left_side: left_side,
+            },
+            HexenLinedefExtra {
+                special: special,
+                args: args,
+                activation: ((flags & ACTIVATION_MASK) as u16) >> 10,
+            },
+        ));
+    }
+    linedefs
+}
@@ -1,4 +1,5 @@
 use numvec::Vec2f;
+use std::io::{IoResult, MemWriter};
 use std::mem;
 use std::vec::Vec;
 use super::archive::Archive;
@@ -6,6 +7,12 @@ use super::types::{WadThing, WadLinedef, WadSidedef, WadVertex, WadSeg,
                    WadSubsector, WadNode, WadSector, VertexId, WadName,
                    LightLevel, SectorId};
 use super::util::from_wad_coords;
+use super::nodes::{NodeFormat, WideSeg, WideSubsector, WideNode,
+                   read_segs, read_subsectors, read_nodes};
+use super::morton::MortonGrid;
+use super::lump_io::ToWriter;
+use super::hexen::{MapFormat, HexenThingExtra, HexenLinedefExtra,
+                   read_hexen_things, read_hexen_linedefs};
 
 
 static THINGS_OFFSET: uint = 1;
@@ -19,14 +26,20 @@ static SECTORS_OFFSET: uint = 8;
 
 
 pub struct Level {
+    pub name: WadName,
     pub things: Vec<WadThing>,
     pub linedefs: Vec<WadLinedef>,
     pub sidedefs: Vec<WadSidedef>,
     pub vertices: Vec<WadVertex>,
-    pub segs: Vec<WadSeg>,
-    pub subsectors: Vec<WadSubsector>,
-    pub nodes: Vec<WadNode>,
+    pub segs: Vec<WideSeg>,
+    pub subsectors: Vec<WideSubsector>,
+    pub nodes: Vec<WideNode>,
     pub sectors: Vec<WadSector>,
+    pub node_format: NodeFormat,
+    pub spatial_index: MortonGrid,
+    pub map_format: MapFormat,
+    pub thing_extras: Option<Vec<HexenThingExtra>>,
+    pub linedef_extras: Option<Vec<HexenLinedefExtra>>,
 }
 
 
@@ -34,12 +47,49 @@ impl Level {
     pub fn from_archive(wad: &mut Archive, name: &WadName) -> Level {
         info!("Reading level data for '{}'...", name);
         let start_index = wad.get_lump_index(name).expect("No such level.");
-        let things = wad.read_lump(start_index + THINGS_OFFSET);
-        let linedefs = wad.read_lump(start_index + LINEDEFS_OFFSET);
-        let vertices = wad.read_lump(start_index + VERTICES_OFFSET);
-        let segs = wad.read_lump(start_index + SEGS_OFFSET);
-        let subsectors = wad.read_lump(start_index + SSECTORS_OFFSET);
-        let nodes = wad.read_lump(start_index + NODES_OFFSET);
+
+        let map_format = MapFormat::detect(wad, start_index);
+        info!("    map format: {}", map_format)
+        let (things, thing_extras) = match map_format {
+            MapFormat::Doom => (wad.read_lump(start_index + THINGS_OFFSET), None),
+            MapFormat::Hexen => {
+                let parsed = read_hexen_things(wad, start_index + THINGS_OFFSET);
+                let (things, extras) = parsed.into_iter().unzip();
+                (things, Some(extras))
+            }
+        };
+        let (linedefs, linedef_extras) = match map_format {
+            MapFormat::Doom =>
+                (wad.read_lump(start_index + LINEDEFS_OFFSET), None),
+            MapFormat::Hexen => {
+                let parsed =
+                    read_hexen_linedefs(wad, start_index + LINEDEFS_OFFSET);
+                let (linedefs, extras) = parsed.into_iter().unzip();
+                (linedefs, Some(extras))
+            }
+        };
+        let mut vertices: Vec<WadVertex> =
+                wad.read_lump(start_index + VERTICES_OFFSET);
+
+        let node_format = NodeFormat::sniff(wad, start_index + NODES_OFFSET);
+        info!("    node format: {}", node_format)
+        // ZDoom's extended node formats pack the seg/subsector/node
+        // sections together inside the NODES lump itself; the SEGS and
+        // SSECTORS lumps are stubs for those formats, so the NODES
+        // index must be used for all three reads rather than each
+        // lump's own vanilla slot.
+        let (segs_index, ssectors_index) = match node_format {
+            NodeFormat::ZDoomExtended | NodeFormat::ZDoomExtendedCompressed =>
+                (start_index + NODES_OFFSET, start_index + NODES_OFFSET),
+            NodeFormat::Vanilla | NodeFormat::DeePBsp =>
+                (start_index + SEGS_OFFSET, start_index + SSECTORS_OFFSET),
+        };
+        let (new_vertices, segs) =
+                read_segs(wad, segs_index, node_format, vertices.len());
+        vertices.extend(new_vertices.into_iter());
+        let vertices = vertices;
+        let subsectors = read_subsectors(wad, ssectors_index, node_format);
+        let nodes = read_nodes(wad, start_index + NODES_OFFSET, node_format);
 
         let mut sidedefs = wad.read_lump::<WadSidedef>(
                 start_index + SIDEDEFS_OFFSET);
@@ -68,7 +118,11 @@ impl Level {
         info!("    {:4} nodes", nodes.len())
         info!("    {:4} sectors", sectors.len())
 
+        let spatial_index = MortonGrid::build(vertices.as_slice(),
+                                              segs.as_slice());
+
         Level {
+            name: name.clone(),
             things: things,
             linedefs: linedefs,
             sidedefs: sidedefs,
@@ -77,7 +131,92 @@ impl Level {
             subsectors: subsectors,
             nodes: nodes,
             sectors: sectors,
+            node_format: node_format,
+            spatial_index: spatial_index,
+            map_format: map_format,
+            thing_extras: thing_extras,
+            linedef_extras: linedef_extras,
+        }
+    }
+
+    /// Re-serializes the lumps `from_archive` reads, decanonicalising
+    /// texture names on the way out. `SEGS`/`SSECTORS`/`NODES` come back
+    /// empty pending a node builder to rebuild the BSP.
+    pub fn write_lumps(&self) -> IoResult<Vec<(WadName, Vec<u8>)>> {
+        let mut things = MemWriter::new();
+        for thing in self.things.iter() {
+            try!(thing.to_writer(&mut things));
+        }
+
+        let mut linedefs = MemWriter::new();
+        for linedef in self.linedefs.iter() {
+            try!(linedef.to_writer(&mut linedefs));
+        }
+
+        let mut sidedefs = MemWriter::new();
+        for sidedef in self.sidedefs.iter() {
+            let mut sidedef = sidedef.clone();
+            sidedef.upper_texture.decanonicalise();
+            sidedef.lower_texture.decanonicalise();
+            sidedef.middle_texture.decanonicalise();
+            try!(sidedef.to_writer(&mut sidedefs));
+        }
+
+        let mut vertices = MemWriter::new();
+        for vertex in self.vertices.iter() {
+            try!(vertex.to_writer(&mut vertices));
         }
+
+        let mut sectors = MemWriter::new();
+        for sector in self.sectors.iter() {
+            let mut sector = sector.clone();
+            sector.floor_texture.decanonicalise();
+            sector.ceiling_texture.decanonicalise();
+            try!(sector.to_writer(&mut sectors));
+        }
+
+        Ok(vec![
+            (WadName::from_str("THINGS"), things.unwrap()),
+            (WadName::from_str("LINEDEFS"), linedefs.unwrap()),
+            (WadName::from_str("SIDEDEFS"), sidedefs.unwrap()),
+            (WadName::from_str("VERTEXES"), vertices.unwrap()),
+            (WadName::from_str("SEGS"), Vec::new()),
+            (WadName::from_str("SSECTORS"), Vec::new()),
+            (WadName::from_str("NODES"), Vec::new()),
+            (WadName::from_str("SECTORS"), sectors.unwrap()),
+        ])
+    }
+
+    /// Writes this level out as a standalone PWAD, loadable back with
+    /// `Level::from_archive` modulo the BSP (see `write_lumps`).
+    pub fn to_archive<W: Writer>(&self, writer: &mut W) -> IoResult<()> {
+        let mut lumps = Vec::new();
+        lumps.push((self.name.clone(), Vec::new()));
+        for lump in try!(self.write_lumps()).into_iter() {
+            lumps.push(lump);
+        }
+
+        let header_size = 12u32;
+        let mut offset = header_size;
+        let mut directory = Vec::new();
+        for &(ref name, ref bytes) in lumps.iter() {
+            directory.push((name.clone(), offset, bytes.len() as u32));
+            offset += bytes.len() as u32;
+        }
+        let directory_offset = offset;
+
+        try!(writer.write(b"PWAD"));
+        try!(writer.write_le_i32(lumps.len() as i32));
+        try!(writer.write_le_i32(directory_offset as i32));
+        for &(_, ref bytes) in lumps.iter() {
+            try!(writer.write(bytes.as_slice()));
+        }
+        for (name, lump_offset, size) in directory.into_iter() {
+            try!(writer.write_le_i32(lump_offset as i32));
+            try!(writer.write_le_i32(size as i32));
+            try!(name.to_writer(writer));
+        }
+        Ok(())
     }
 
     pub fn vertex(&self, id: VertexId) -> Vec2f {
@@ -85,32 +224,53 @@ impl Level {
                         self.vertices[id as uint].y)
     }
 
-    pub fn seg_linedef<'a>(&'a self, seg: &WadSeg) -> &'a WadLinedef {
+    /// Walks the BSP tree (root is the last entry in `nodes`) to find
+    /// the subsector containing world point `p`.
+    pub fn subsector_at(&self, p: Vec2f) -> &WideSubsector {
+        let mut index = (self.nodes.len() - 1) as u32;
+        loop {
+            if WideNode::is_subsector_child(index) {
+                return &self.subsectors[WideNode::child_index(index) as uint];
+            }
+            let node = &self.nodes[index as uint];
+            let cross = node.dx as f32 * (p.y - node.y as f32) -
+                        node.dy as f32 * (p.x - node.x as f32);
+            index = if cross < 0.0 { node.children[0] } else { node.children[1] };
+        }
+    }
+
+    pub fn sector_at(&self, p: Vec2f) -> &WadSector {
+        let ssector = self.subsector_at(p);
+        let segs = self.ssector_segs(ssector);
+        self.seg_sector(&segs[0])
+    }
+
+    pub fn seg_linedef<'a>(&'a self, seg: &WideSeg) -> &'a WadLinedef {
         &self.linedefs[seg.linedef as uint]
     }
 
-    pub fn seg_vertices(&self, seg: &WadSeg) -> (Vec2f, Vec2f) {
+    pub fn seg_vertices(&self, seg: &WideSeg) -> (Vec2f, Vec2f) {
         (self.vertex(seg.start_vertex), self.vertex(seg.end_vertex))
     }
 
-    pub fn seg_sidedef<'a>(&'a self, seg: &WadSeg) -> &'a WadSidedef {
+    pub fn seg_sidedef<'a>(&'a self, seg: &WideSeg) -> &'a WadSidedef {
         let line = self.seg_linedef(seg);
         if seg.direction == 0 { self.right_sidedef(line).unwrap() }
         else { self.left_sidedef(line).unwrap() }
     }
 
-    pub fn seg_back_sidedef<'a>(&'a self, seg: &WadSeg)
+    pub fn seg_back_sidedef<'a>(&'a self, seg: &WideSeg)
             -> Option<&'a WadSidedef> {
         let line = self.seg_linedef(seg);
         if seg.direction == 1 { self.right_sidedef(line) }
         else { self.left_sidedef(line) }
     }
 
-    pub fn seg_sector<'a>(&'a self, seg: &WadSeg) -> &'a WadSector {
+    pub fn seg_sector<'a>(&'a self, seg: &WideSeg) -> &'a WadSector {
         self.sidedef_sector(self.seg_sidedef(seg))
     }
 
-    pub fn seg_back_sector<'a>(&'a self, seg: &WadSeg)
+    pub fn seg_back_sector<'a>(&'a self, seg: &WideSeg)
             -> Option<&'a WadSector> {
         self.seg_back_sidedef(seg).map(|s| self.sidedef_sector(s))
     }
@@ -135,7 +295,7 @@ impl Level {
         &self.sectors[sidedef.sector as uint]
     }
 
-    pub fn ssector_segs<'a>(&'a self, ssector: &WadSubsector) -> &'a [WadSeg] {
+    pub fn ssector_segs<'a>(&'a self, ssector: &WideSubsector) -> &'a [WideSeg] {
         self.segs.slice(ssector.first_seg as uint,
                         (ssector.first_seg as uint + ssector.num_segs as uint))
     }
@@ -170,3 +330,111 @@ impl Level {
         min_light
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use numvec::Vec2f;
+    use std::io::{BufReader, MemWriter};
+    use super::Level;
+    use super::super::nodes::{NodeFormat, WideNode, WideSubsector, SUBSECTOR_BIT};
+    use super::super::hexen::MapFormat;
+    use super::super::morton::MortonGrid;
+    use super::super::lump_io::FromReader;
+    use super::super::types::{WadName, WadThing, WadVertex};
+
+    fn level_with_one_split() -> Level {
+        let node = WideNode {
+            x: 0, y: 0, dx: 1, dy: 0,
+            bbox: [[0i16, ..4], ..2],
+            children: [SUBSECTOR_BIT, SUBSECTOR_BIT | 1],
+        };
+        Level {
+            name: WadName::from_str("TEST"),
+            things: Vec::new(),
+            linedefs: Vec::new(),
+            sidedefs: Vec::new(),
+            vertices: Vec::new(),
+            segs: Vec::new(),
+            subsectors: vec![
+                WideSubsector { num_segs: 0, first_seg: 0 },
+                WideSubsector { num_segs: 0, first_seg: 0 },
+            ],
+            nodes: vec![node],
+            sectors: Vec::new(),
+            node_format: NodeFormat::Vanilla,
+            spatial_index: MortonGrid::build(&[] as &[WadVertex], &[]),
+            map_format: MapFormat::Doom,
+            thing_extras: None,
+            linedef_extras: None,
+        }
+    }
+
+    // Node is `dx=1, dy=0, x=0, y=0`, so `cross = p.y`: a negative cross
+    // product must pick `children[0]` (the right child) and a
+    // non-negative one `children[1]` (the left child).
+
+    #[test]
+    fn subsector_at_picks_the_right_child_on_negative_cross_product() {
+        let level = level_with_one_split();
+        let right = level.subsector_at(Vec2f { x: 0.0, y: -10.0 });
+        assert!(right as *const _ == &level.subsectors[0] as *const _);
+    }
+
+    #[test]
+    fn subsector_at_picks_the_left_child_on_non_negative_cross_product() {
+        let level = level_with_one_split();
+        let left = level.subsector_at(Vec2f { x: 0.0, y: 10.0 });
+        assert!(left as *const _ == &level.subsectors[1] as *const _);
+    }
+
+    #[test]
+    fn to_archive_writes_a_directory_that_resolves_back_to_its_lumps() {
+        let level = Level {
+            name: WadName::from_str("MAP01"),
+            things: vec![WadThing { x: 10, y: 20, angle: 90, thing_type: 1, flags: 7 }],
+            linedefs: Vec::new(),
+            sidedefs: Vec::new(),
+            vertices: Vec::new(),
+            segs: Vec::new(),
+            subsectors: Vec::new(),
+            nodes: Vec::new(),
+            sectors: Vec::new(),
+            node_format: NodeFormat::Vanilla,
+            spatial_index: MortonGrid::build(&[] as &[WadVertex], &[]),
+            map_format: MapFormat::Doom,
+            thing_extras: None,
+            linedef_extras: None,
+        };
+
+        let mut writer = MemWriter::new();
+        level.to_archive(&mut writer).unwrap();
+        let bytes = writer.unwrap();
+
+        assert_eq!(bytes.slice(0, 4), b"PWAD");
+        let mut header = BufReader::new(bytes.slice(4, 12));
+        let lump_count = header.read_le_i32().unwrap();
+        let directory_offset = header.read_le_i32().unwrap();
+        assert_eq!(lump_count, 9);  // the level marker plus 8 named lumps.
+
+        let mut directory = BufReader::new(bytes.slice_from(directory_offset as uint));
+        let (_, marker_size) =
+                (directory.read_le_i32().unwrap(), directory.read_le_i32().unwrap());
+        let marker_name: WadName = FromReader::from_reader(&mut directory).unwrap();
+        assert_eq!(marker_size, 0);
+        assert_eq!(marker_name, level.name);
+
+        let (things_offset, things_size) =
+                (directory.read_le_i32().unwrap(), directory.read_le_i32().unwrap());
+        let things_name: WadName = FromReader::from_reader(&mut directory).unwrap();
+        assert_eq!(things_name, WadName::from_str("THINGS"));
+        assert_eq!(things_size, 10);  // one WadThing record.
+
+        let mut things_reader = BufReader::new(
+                bytes.slice(things_offset as uint,
+                           things_offset as uint + things_size as uint));
+        let thing: WadThing = FromReader::from_reader(&mut things_reader).unwrap();
+        assert_eq!(thing.x, 10);
+        assert_eq!(thing.y, 20);
+    }
+}
@@ -0,0 +1,13 @@
+pub use self::archive::Archive;
+pub use self::level::Level;
+
+pub mod archive;
+pub mod compression;
+pub mod cursor;
+pub mod hexen;
+pub mod level;
+pub mod lump_io;
+pub mod morton;
+pub mod nodes;
+pub mod types;
+pub mod util;
@@ -0,0 +1,149 @@
+//! Morton (Z-order) spatial index over level geometry: buckets segs by
+//! the Morton code of their quantized start vertex, so a neighbourhood
+//! query only has to probe a handful of buckets instead of every seg.
+
+use std::collections::HashMap;
+use std::cmp;
+use std::i32;
+use std::vec::Vec;
+use super::types::WadVertex;
+use super::nodes::WideSeg;
+
+/// The grid has `1 << GRID_BITS` cells per axis.
+static GRID_BITS: uint = 10;
+
+pub type MortonCode = u32;
+
+fn spread(v: u32) -> u32 {
+    let mut v = v & 0x0000ffff;
+    v = (v | (v << 8)) & 0x00ff00ff;
+    v = (v | (v << 4)) & 0x0f0f0f0f;
+    v = (v | (v << 2)) & 0x33333333;
+    v = (v | (v << 1)) & 0x55555555;
+    v
+}
+
+/// Interleaves the bits of `x` and `y`, `y` occupying the odd bit
+/// positions, so that spatially close `(x, y)` quantized cells map to
+/// close-ish codes.
+pub fn morton_code(x: u32, y: u32) -> MortonCode {
+    spread(x) | (spread(y) << 1)
+}
+
+/// Buckets seg indices by the Morton code of their start vertex, over a
+/// `1 << GRID_BITS` square grid spanning the level's vertices.
+pub struct MortonGrid {
+    min_x: i32,
+    min_y: i32,
+    cell_size: i32,
+    buckets: HashMap<MortonCode, Vec<uint>>,
+}
+
+impl MortonGrid {
+    pub fn build(vertices: &[WadVertex], segs: &[WideSeg]) -> MortonGrid {
+        let (mut min_x, mut min_y) = (i32::MAX, i32::MAX);
+        let (mut max_x, mut max_y) = (i32::MIN, i32::MIN);
+        for v in vertices.iter() {
+            let (x, y) = (v.x as i32, v.y as i32);
+            if x < min_x { min_x = x; }
+            if y < min_y { min_y = y; }
+            if x > max_x { max_x = x; }
+            if y > max_y { max_y = y; }
+        }
+        if vertices.len() == 0 { min_x = 0; min_y = 0; max_x = 0; max_y = 0; }
+
+        let span = cmp::max(max_x - min_x, max_y - min_y) + 1;
+        let cells = (1 << GRID_BITS) as i32;
+        let cell_size = cmp::max(1, (span + cells - 1) / cells);
+
+        let mut grid = MortonGrid {
+            min_x: min_x,
+            min_y: min_y,
+            cell_size: cell_size,
+            buckets: HashMap::new(),
+        };
+        for (seg_index, seg) in segs.iter().enumerate() {
+            if (seg.start_vertex as uint) >= vertices.len() { continue; }
+            let v = vertices[seg.start_vertex as uint];
+            let code = grid.code_for(v.x as i32, v.y as i32);
+            if !grid.buckets.contains_key(&code) {
+                grid.buckets.insert(code, Vec::new());
+            }
+            grid.buckets.get_mut(&code).unwrap().push(seg_index);
+        }
+        grid
+    }
+
+    fn cell_coords(&self, x: i32, y: i32) -> (u32, u32) {
+        let qx = (x - self.min_x) / self.cell_size;
+        let qy = (y - self.min_y) / self.cell_size;
+        (qx as u32, qy as u32)
+    }
+
+    fn code_for(&self, x: i32, y: i32) -> MortonCode {
+        let (qx, qy) = self.cell_coords(x, y);
+        morton_code(qx, qy)
+    }
+
+    /// Returns the seg indices bucketed in the cell containing `(x, y)`
+    /// and its 8 neighbours, a cheap broad-phase candidate set for
+    /// queries that would otherwise scan every seg in the level.
+    pub fn segs_near<'a>(&'a self, x: i32, y: i32) -> Vec<uint> {
+        let (qx, qy) = self.cell_coords(x, y);
+        let mut out = Vec::new();
+        for dy in range(-1i32, 2) {
+            for dx in range(-1i32, 2) {
+                let nx = qx as i32 + dx;
+                let ny = qy as i32 + dy;
+                if nx < 0 || ny < 0 { continue; }
+                let code = morton_code(nx as u32, ny as u32);
+                if let Some(bucket) = self.buckets.get(&code) {
+                    out.push_all(bucket.as_slice());
+                }
+            }
+        }
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::{MortonGrid, WideSeg, WadVertex, morton_code};
+
+    fn seg(start_vertex: u32) -> WideSeg {
+        WideSeg {
+            start_vertex: start_vertex, end_vertex: start_vertex,
+            angle: 0, linedef: 0, direction: 0, offset: 0,
+        }
+    }
+
+    #[test]
+    fn morton_code_interleaves_bits() {
+        assert_eq!(morton_code(0, 0), 0);
+        assert_eq!(morton_code(1, 0), 1);
+        assert_eq!(morton_code(0, 1), 2);
+        assert_eq!(morton_code(1, 1), 3);
+    }
+
+    #[test]
+    fn segs_near_finds_seg_in_same_cell() {
+        let vertices = vec![WadVertex { x: 0, y: 0 }, WadVertex { x: 4000, y: 4000 }];
+        let segs = vec![seg(0), seg(1)];
+        let grid = MortonGrid::build(vertices.as_slice(), segs.as_slice());
+
+        let near_origin = grid.segs_near(0, 0);
+        assert!(near_origin.contains(&0u));
+    }
+
+    #[test]
+    fn segs_near_skips_unrelated_far_cell() {
+        let vertices = vec![WadVertex { x: 0, y: 0 }, WadVertex { x: 30000, y: 30000 }];
+        let segs = vec![seg(0), seg(1)];
+        let grid = MortonGrid::build(vertices.as_slice(), segs.as_slice());
+
+        let near_origin: Vec<uint> = grid.segs_near(0, 0);
+        assert!(!near_origin.contains(&1u));
+    }
+}
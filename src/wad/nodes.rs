@@ -0,0 +1,382 @@
+//! Node, seg and subsector formats beyond vanilla's 16-bit layout.
+//! `NodeFormat::sniff` reads the NODES lump's magic signature, and
+//! `read_segs`/`read_subsectors`/`read_nodes` parse whichever format is
+//! present into a single widened representation.
+
+use std::vec::Vec;
+use super::archive::Archive;
+use super::compression::decode_lump;
+use super::cursor::Cursor;
+use super::types::{WadSeg, WadSubsector, WadNode, WadVertex};
+
+
+/// The node/seg/subsector lump format detected for a level.
+#[deriving(Copy, PartialEq, Show)]
+pub enum NodeFormat {
+    /// The original vanilla Doom layout: 16-bit indices throughout.
+    Vanilla,
+    /// DeePsea/DeePBSP-style nodes, identified by a `"gNdN"` signature.
+    DeePBsp,
+    /// ZDoom's uncompressed extended nodes, identified by `"XNOD"`.
+    ZDoomExtended,
+    /// ZDoom's zlib-compressed extended nodes, identified by `"ZNOD"`.
+    ZDoomExtendedCompressed,
+}
+
+impl NodeFormat {
+    /// Sniffs the format of the NODES lump at `nodes_index` by reading
+    /// its first four bytes as a magic signature.
+    pub fn sniff(wad: &mut Archive, nodes_index: uint) -> NodeFormat {
+        let magic: Vec<u8> = wad.read_lump(nodes_index);
+        if magic.len() < 4 { return NodeFormat::Vanilla; }
+        match magic.slice(0, 4) {
+            b"XNOD" => NodeFormat::ZDoomExtended,
+            b"ZNOD" => NodeFormat::ZDoomExtendedCompressed,
+            b"gNd2" | b"gNd4" | b"gNd5" => NodeFormat::DeePBsp,
+            _ => NodeFormat::Vanilla,
+        }
+    }
+}
+
+
+/// A `WadSeg` with its vertex and linedef indices widened to `u32`, so
+/// segs read from any node format share one in-memory layout.
+pub struct WideSeg {
+    pub start_vertex: u32,
+    pub end_vertex: u32,
+    pub angle: i16,
+    pub linedef: u32,
+    pub direction: i16,
+    pub offset: i16,
+}
+
+
+/// A `WadSubsector` with its seg range widened to `u32`.
+pub struct WideSubsector {
+    pub num_segs: u32,
+    pub first_seg: u32,
+}
+
+
+/// A `WadNode` with its child indices widened to `u32`.
+pub struct WideNode {
+    pub x: i16,
+    pub y: i16,
+    pub dx: i16,
+    pub dy: i16,
+    pub bbox: [[i16, ..4], ..2],
+    pub children: [u32, ..2],
+}
+
+/// Set on a `WideNode` child index when it refers to a subsector rather
+/// than another node.
+pub static SUBSECTOR_BIT: u32 = 1 << 31;
+
+impl WideNode {
+    pub fn is_subsector_child(child: u32) -> bool {
+        child & SUBSECTOR_BIT != 0
+    }
+
+    pub fn child_index(child: u32) -> u32 {
+        child & !SUBSECTOR_BIT
+    }
+}
+
+
+/// `index` is the lump to read `format`'s seg data from: the SEGS slot
+/// for `Vanilla`/`DeePBsp`, but the NODES lump for ZDoom's extended
+/// formats, which pack segs/subsectors/nodes/new vertices together
+/// there. `num_vertices` is the VERTEXES count read so far, used to
+/// resolve extended segs' new-vertex indices; any new vertices are
+/// returned for the caller to append so vertex indices stay uniform.
+pub fn read_segs(wad: &mut Archive, index: uint, format: NodeFormat,
+                 num_vertices: uint) -> (Vec<WadVertex>, Vec<WideSeg>) {
+    match format {
+        NodeFormat::Vanilla => {
+            let raw: Vec<WadSeg> = wad.read_lump(index);
+            let segs = raw.iter().map(|s| WideSeg {
+                start_vertex: s.start_vertex as u32,
+                end_vertex: s.end_vertex as u32,
+                angle: s.angle,
+                linedef: s.linedef as u32,
+                direction: s.direction,
+                offset: s.offset,
+            }).collect();
+            (Vec::new(), segs)
+        }
+        NodeFormat::DeePBsp => {
+            let raw: Vec<u8> = wad.read_lump(index);
+            (Vec::new(), read_deepbsp_segs(raw.as_slice()))
+        }
+        NodeFormat::ZDoomExtended | NodeFormat::ZDoomExtendedCompressed => {
+            let raw: Vec<u8> = wad.read_lump(index);
+            read_extended_segs(decode_lump(raw.as_slice()).as_slice(),
+                               num_vertices as u32)
+        }
+    }
+}
+
+pub fn read_subsectors(wad: &mut Archive, index: uint, format: NodeFormat)
+        -> Vec<WideSubsector> {
+    match format {
+        NodeFormat::Vanilla => {
+            let raw: Vec<WadSubsector> = wad.read_lump(index);
+            raw.iter().map(|s| WideSubsector {
+                num_segs: s.num_segs as u32,
+                first_seg: s.first_seg as u32,
+            }).collect()
+        }
+        NodeFormat::DeePBsp => {
+            let raw: Vec<u8> = wad.read_lump(index);
+            read_deepbsp_subsectors(raw.as_slice())
+        }
+        NodeFormat::ZDoomExtended | NodeFormat::ZDoomExtendedCompressed => {
+            let raw: Vec<u8> = wad.read_lump(index);
+            read_extended_subsectors(decode_lump(raw.as_slice()).as_slice())
+        }
+    }
+}
+
+pub fn read_nodes(wad: &mut Archive, index: uint, format: NodeFormat)
+        -> Vec<WideNode> {
+    match format {
+        NodeFormat::Vanilla => {
+            let raw: Vec<WadNode> = wad.read_lump(index);
+            // Vanilla marks subsector children with bit 15; move that
+            // flag to `SUBSECTOR_BIT`.
+            let widen = |c: u16| -> u32 {
+                if c & 0x8000 != 0 { SUBSECTOR_BIT | (c & 0x7fff) as u32 }
+                else { c as u32 }
+            };
+            raw.iter().map(|n| WideNode {
+                x: n.x, y: n.y, dx: n.dx, dy: n.dy, bbox: n.bbox,
+                children: [widen(n.children[0]), widen(n.children[1])],
+            }).collect()
+        }
+        NodeFormat::DeePBsp => {
+            let raw: Vec<u8> = wad.read_lump(index);
+            read_deepbsp_nodes(raw.slice_from(4))
+        }
+        NodeFormat::ZDoomExtended | NodeFormat::ZDoomExtendedCompressed => {
+            let raw: Vec<u8> = wad.read_lump(index);
+            read_extended_nodes(decode_lump(raw.as_slice()).as_slice())
+        }
+    }
+}
+
+
+// DeePBSP format: fixed-width records like vanilla, but with 32-bit
+// vertex, subsector seg-range and child indices in place of vanilla's
+// 16-bit ones (linedef indices stay 16-bit). Unlike the ZDoom extended
+// format these aren't prefixed with counts: the record count is just the
+// lump length (past the NODES lump's 4-byte signature) divided by the
+// record width.
+static DEEPBSP_SEG_SIZE: uint = 16;
+static DEEPBSP_SUBSECTOR_SIZE: uint = 8;
+static DEEPBSP_NODE_SIZE: uint = 32;
+
+fn read_deepbsp_segs(data: &[u8]) -> Vec<WideSeg> {
+    (0u..data.len() / DEEPBSP_SEG_SIZE).map(|i| {
+        let mut cursor = Cursor::new(
+                data.slice(i * DEEPBSP_SEG_SIZE, (i + 1) * DEEPBSP_SEG_SIZE));
+        WideSeg {
+            start_vertex: cursor.u32(),
+            end_vertex: cursor.u32(),
+            angle: cursor.i16(),
+            linedef: cursor.u16() as u32,
+            direction: cursor.i16(),
+            offset: cursor.i16(),
+        }
+    }).collect()
+}
+
+fn read_deepbsp_subsectors(data: &[u8]) -> Vec<WideSubsector> {
+    (0u..data.len() / DEEPBSP_SUBSECTOR_SIZE).map(|i| {
+        let mut cursor = Cursor::new(
+                data.slice(i * DEEPBSP_SUBSECTOR_SIZE,
+                          (i + 1) * DEEPBSP_SUBSECTOR_SIZE));
+        WideSubsector { num_segs: cursor.u32(), first_seg: cursor.u32() }
+    }).collect()
+}
+
+fn read_deepbsp_nodes(data: &[u8]) -> Vec<WideNode> {
+    (0u..data.len() / DEEPBSP_NODE_SIZE).map(|i| {
+        let mut cursor = Cursor::new(
+                data.slice(i * DEEPBSP_NODE_SIZE, (i + 1) * DEEPBSP_NODE_SIZE));
+        let x = cursor.i16();
+        let y = cursor.i16();
+        let dx = cursor.i16();
+        let dy = cursor.i16();
+        let mut bbox = [[0i16, ..4], ..2];
+        for side in range(0u, 2) {
+            for corner in range(0u, 4) {
+                bbox[side][corner] = cursor.i16();
+            }
+        }
+        let children = [cursor.u32(), cursor.u32()];
+        WideNode { x: x, y: y, dx: dx, dy: dy, bbox: bbox, children: children }
+    }).collect()
+}
+
+
+// Extended (ZDoom "XNOD") format: past its 4-byte signature (already
+// stripped by `decode_lump`), the NODES lump holds the 32-bit original
+// vertex count, then the new vertices, subsectors, segs and nodes one
+// after another, each section prefixed with its own 32-bit count. `decode_lump` hands this the same payload
+// whether the lump was compressed ("ZNOD"/"ZGLN") or not.
+
+/// Set on a seg's `start_vertex`/`end_vertex` in the extended format
+/// when it refers to one of that NODES lump's own new vertices rather
+/// than one from VERTEXES.
+static NEW_VERTEX_BIT: u32 = 1 << 31;
+
+/// New vertices are stored as 16.16 fixed-point; `WadVertex` only has
+/// room for the integer part.
+fn read_extended_vertices(cursor: &mut Cursor, count: uint) -> Vec<WadVertex> {
+    (0u..count).map(|_| {
+        let x = cursor.i32();
+        let y = cursor.i32();
+        WadVertex { x: (x >> 16) as i16, y: (y >> 16) as i16 }
+    }).collect()
+}
+
+fn resolve_vertex(raw: u32, num_vertices: u32) -> u32 {
+    if raw & NEW_VERTEX_BIT != 0 {
+        num_vertices + (raw & !NEW_VERTEX_BIT)
+    } else {
+        raw
+    }
+}
+
+fn read_extended_segs(data: &[u8], num_vertices: u32) -> (Vec<WadVertex>, Vec<WideSeg>) {
+    let mut cursor = Cursor::new(data);
+    cursor.u32();  // num_org_vertices: informational only, not relied on.
+    let num_new_vertices = cursor.u32();
+    let new_vertices = read_extended_vertices(&mut cursor, num_new_vertices as uint);
+    let num_subsectors = cursor.u32();
+    for _ in range(0u, num_subsectors as uint) {
+        cursor.u32();
+    }
+    let num_segs = cursor.u32();
+    let segs = (0u..num_segs as uint).map(|_| {
+        let start_vertex = resolve_vertex(cursor.u32(), num_vertices);
+        let end_vertex = resolve_vertex(cursor.u32(), num_vertices);
+        let linedef = cursor.u16() as u32;
+        let direction = cursor.u8() as i16;
+        WideSeg {
+            start_vertex: start_vertex,
+            end_vertex: end_vertex,
+            angle: 0,
+            linedef: linedef,
+            direction: direction,
+            offset: 0,
+        }
+    }).collect();
+    (new_vertices, segs)
+}
+
+fn read_extended_subsectors(data: &[u8]) -> Vec<WideSubsector> {
+    let mut cursor = Cursor::new(data);
+    cursor.u32();  // num_org_vertices
+    let num_new_vertices = cursor.u32();
+    read_extended_vertices(&mut cursor, num_new_vertices as uint);
+    let num_subsectors = cursor.u32();
+    let mut first_seg = 0u32;
+    (0u..num_subsectors as uint).map(|_| {
+        let num_segs = cursor.u32();
+        let subsector = WideSubsector { num_segs: num_segs, first_seg: first_seg };
+        first_seg += num_segs;
+        subsector
+    }).collect()
+}
+
+fn read_extended_nodes(data: &[u8]) -> Vec<WideNode> {
+    let mut cursor = Cursor::new(data);
+    cursor.u32();  // num_org_vertices
+    let num_new_vertices = cursor.u32();
+    read_extended_vertices(&mut cursor, num_new_vertices as uint);
+    let num_subsectors = cursor.u32();
+    for _ in range(0u, num_subsectors as uint) {
+        cursor.u32();
+    }
+    let num_segs = cursor.u32();
+    for _ in range(0u, num_segs as uint) {
+        cursor.u32();
+        cursor.u32();
+        cursor.u16();
+        cursor.u8();
+    }
+    let num_nodes = cursor.u32();
+    (0u..num_nodes as uint).map(|_| {
+        let x = cursor.i16();
+        let y = cursor.i16();
+        let dx = cursor.i16();
+        let dy = cursor.i16();
+        let mut bbox = [[0i16, ..4], ..2];
+        for side in range(0u, 2) {
+            for corner in range(0u, 4) {
+                bbox[side][corner] = cursor.i16();
+            }
+        }
+        let children = [cursor.u32(), cursor.u32()];
+        WideNode { x: x, y: y, dx: dx, dy: dy, bbox: bbox, children: children }
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::{read_deepbsp_segs, read_extended_nodes, WideNode};
+
+    fn push_u16(buf: &mut Vec<u8>, v: u16) {
+        buf.push((v & 0xff) as u8);
+        buf.push((v >> 8) as u8);
+    }
+
+    fn push_u32(buf: &mut Vec<u8>, v: u32) {
+        buf.push((v & 0xff) as u8);
+        buf.push(((v >> 8) & 0xff) as u8);
+        buf.push(((v >> 16) & 0xff) as u8);
+        buf.push(((v >> 24) & 0xff) as u8);
+    }
+
+    #[test]
+    fn deepbsp_seg_widens_indices_past_u16() {
+        let mut buf = Vec::new();
+        push_u32(&mut buf, 70000);  // start_vertex, overflows a u16.
+        push_u32(&mut buf, 70001);  // end_vertex
+        push_u16(&mut buf, 0);      // angle
+        push_u16(&mut buf, 7);      // linedef
+        push_u16(&mut buf, 1);      // direction
+        push_u16(&mut buf, 0);      // offset
+
+        let segs = read_deepbsp_segs(buf.as_slice());
+
+        assert_eq!(segs.len(), 1);
+        assert_eq!(segs[0].start_vertex, 70000);
+        assert_eq!(segs[0].end_vertex, 70001);
+        assert_eq!(segs[0].linedef, 7);
+    }
+
+    #[test]
+    fn extended_nodes_widen_children_past_u16() {
+        let mut buf = Vec::new();
+        push_u32(&mut buf, 0);  // num_org_vertices
+        push_u32(&mut buf, 0);  // num_new_vertices
+        push_u32(&mut buf, 0);  // num_subsectors
+        push_u32(&mut buf, 0);  // num_segs
+        push_u32(&mut buf, 1);  // num_nodes
+        for _ in range(0u, 4) { push_u16(&mut buf, 0); }   // x, y, dx, dy
+        for _ in range(0u, 8) { push_u16(&mut buf, 0); }   // bbox[2][4]
+        push_u32(&mut buf, 70000);           // children[0], overflows a u16.
+        push_u32(&mut buf, 1u32 << 31 | 3);  // children[1]: subsector 3.
+
+        let nodes = read_extended_nodes(buf.as_slice());
+
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].children[0], 70000);
+        assert!(WideNode::is_subsector_child(nodes[0].children[1]));
+        assert_eq!(WideNode::child_index(nodes[0].children[1]), 3);
+    }
+}